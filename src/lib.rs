@@ -14,7 +14,7 @@ use actix_web::{
     web::Json,
     Error,
 };
-use actix_web::{http, HttpResponse};
+use actix_web::{http, HttpRequest, HttpResponse};
 use futures::future::{ok, Either, FutureResult};
 use futures::{Async, Future, Poll};
 use metrics::{Recorder, SetRecorderError};
@@ -35,11 +35,36 @@ use std::sync::Arc;
 use std::thread;
 use std::time::{Duration, SystemTime};
 
+mod prometheus;
 mod statsd_metrics;
 
+/// Placeholder `path` label for requests that didn't match any registered
+/// resource, used when labelling by route pattern.
+const UNMATCHED_PATH: &str = "<unmatched>";
+
+/// Compare two strings without branching on the value of individual bytes,
+/// so a mismatched `Authorization` header doesn't leak how many leading
+/// bytes of the token matched through response timing.
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Output format served from the metrics endpoint.
+#[derive(Clone, Copy, PartialEq)]
+enum Exposition {
+    /// A JSON map of metric name to its rendered value (the original format).
+    Json,
+    /// Prometheus text exposition format, version 0.0.4.
+    Prometheus,
+}
+
 #[derive(Clone)]
 #[must_use = "must be set up as a middleware for actix-web"]
-/// By default two metrics are tracked (this assumes the namespace `actix_web_prom`):
+/// By default three metrics are tracked (this assumes the namespace `actix_web_prom`):
 ///
 /// This uses the generic metrics crate which allows you to :
 ///   - Push histograms, gauges and counters to a receiver
@@ -55,17 +80,66 @@ mod statsd_metrics;
 ///
 ///   - `http_requests_duration` (labels: endpoint, method,
 ///    status): histogram of request durations for each endpoint.
+///
+///   - `http_response_size_bytes` (labels: path, method, status): histogram of response
+///    body sizes for each endpoint.
 pub struct Metrics {
     pub(crate) namespace: String,
     pub(crate) path: String,
     exporter: Box<StatsdExporter<Controller, StatsdObserverBuilder>>,
     sink: Sink,
+    exposition: Exposition,
+    use_path_pattern: bool,
+    auth_token: Option<String>,
+    duration_buckets: Option<Vec<u64>>,
 }
 
-impl Metrics {
-    /// Create a new Metrics. You set the namespace and the metrics endpoint
-    /// through here.
-    pub fn new(path: &str, namespace: &str) -> Self {
+/// Builder for [`Metrics`], for overriding the exporter flush interval or
+/// the `http_requests_duration` histogram's bucket bounds. Build with
+/// [`Metrics::builder`].
+pub struct MetricsBuilder {
+    path: String,
+    namespace: String,
+    flush_interval: Duration,
+    duration_buckets: Option<Vec<u64>>,
+    exposition: Exposition,
+}
+
+impl MetricsBuilder {
+    fn new(path: &str, namespace: &str) -> Self {
+        MetricsBuilder {
+            path: path.to_string(),
+            namespace: namespace.to_string(),
+            flush_interval: Duration::from_secs(5),
+            duration_buckets: None,
+            exposition: Exposition::Json,
+        }
+    }
+
+    /// Explicit bucket bounds, in milliseconds, for the
+    /// `http_requests_duration` histogram's Prometheus `_bucket` series.
+    /// When unset, bounds are derived from the samples seen so far.
+    pub fn duration_buckets(mut self, buckets: &[u64]) -> Self {
+        self.duration_buckets = Some(buckets.to_vec());
+        self
+    }
+
+    /// How often the statsd exporter drains and pushes the latest
+    /// metrics. Defaults to 5 seconds.
+    pub fn flush_interval(mut self, interval: Duration) -> Self {
+        self.flush_interval = interval;
+        self
+    }
+
+    /// Serve the snapshot in Prometheus text exposition format instead of
+    /// JSON, so it can be scraped directly by a Prometheus server.
+    pub fn prometheus(mut self) -> Self {
+        self.exposition = Exposition::Prometheus;
+        self
+    }
+
+    /// Build the configured `Metrics`.
+    pub fn build(self) -> Metrics {
         let receiver = Receiver::builder()
             .build()
             .expect("failed to create receiver");
@@ -73,45 +147,149 @@ impl Metrics {
         let exporter = StatsdExporter::new(
             controller.clone(),
             StatsdObserverBuilder::new(),
-            Duration::from_secs(5),
+            self.flush_interval,
         );
         let m = Metrics {
-            namespace: namespace.to_string(),
-            path: path.to_string(),
+            namespace: self.namespace,
+            path: self.path,
             exporter: Box::new(exporter),
             sink: receiver.get_sink(),
+            exposition: self.exposition,
+            use_path_pattern: true,
+            auth_token: None,
+            duration_buckets: self.duration_buckets,
         };
         receiver.install();
         m
     }
+}
 
-    fn update_metrics(&self, path: &str, method: &Method, status: StatusCode, clock: SystemTime) {
+impl Metrics {
+    /// Create a new Metrics. You set the namespace and the metrics endpoint
+    /// through here. The endpoint serves the snapshot as JSON.
+    pub fn new(path: &str, namespace: &str) -> Self {
+        Self::builder(path, namespace).build()
+    }
+
+    /// Start building a Metrics with non-default settings, such as the
+    /// exporter flush interval, the `http_requests_duration` histogram's
+    /// bucket bounds, or Prometheus exposition.
+    pub fn builder(path: &str, namespace: &str) -> MetricsBuilder {
+        MetricsBuilder::new(path, namespace)
+    }
+
+    /// Create a new Metrics whose endpoint serves the snapshot in
+    /// Prometheus text exposition format instead of JSON, so it can be
+    /// scraped directly by a Prometheus server. For Prometheus output with
+    /// custom bucket bounds or flush interval, use
+    /// `Metrics::builder(..).prometheus()` instead.
+    pub fn new_prometheus(path: &str, namespace: &str) -> Self {
+        Self::builder(path, namespace).prometheus().build()
+    }
+
+    /// Label requests with the raw request path (e.g. `/users/1`) instead
+    /// of the matched route pattern (e.g. `/users/{id}`). Off by default:
+    /// labelling by raw path lets every distinct URL blow up the `path`
+    /// label's cardinality.
+    pub fn with_raw_path(mut self) -> Self {
+        self.use_path_pattern = false;
+        self
+    }
+
+    /// Require a shared secret on the metrics endpoint. Once set, GET
+    /// requests to `path` must carry a matching `Authorization: Bearer <token>`
+    /// header or they are answered with `401 Unauthorized` instead of the
+    /// snapshot.
+    pub fn with_auth_token<T: Into<String>>(mut self, token: T) -> Self {
+        self.auth_token = Some(token.into());
+        self
+    }
+
+    /// Whether `req` is allowed to read the metrics endpoint: always true
+    /// when no `auth_token` is configured, otherwise requires a matching
+    /// `Authorization: Bearer <token>` header.
+    fn is_authorized(&self, req: &HttpRequest) -> bool {
+        match &self.auth_token {
+            None => true,
+            Some(token) => {
+                let expected = format!("Bearer {}", token);
+                req.headers()
+                    .get(http::header::AUTHORIZATION)
+                    .and_then(|v| v.to_str().ok())
+                    .map(|v| constant_time_eq(v, &expected))
+                    .unwrap_or(false)
+            }
+        }
+    }
+
+    /// Returns a clone of the underlying `metrics_runtime::Sink` so
+    /// application code can publish its own counters, gauges and
+    /// histograms through the same receiver as the built-in HTTP metrics
+    /// and have them appear in the same `/metrics` snapshot.
+    pub fn sink(&self) -> Sink {
+        self.sink.clone()
+    }
+
+    fn update_metrics(
+        &self,
+        path: &str,
+        method: &Method,
+        status: StatusCode,
+        clock: SystemTime,
+        response_size: usize,
+    ) {
         let p = Cow::from(path).into_owned();
         let m = Cow::from(method.as_str()).into_owned();
         let st = Cow::from(status.as_str()).into_owned();
         let labels: Vec<Label> = labels!("path" => p, "method" => m, "status" => st);
         if let Ok(elapsed) = clock.elapsed() {
-            let duration = (elapsed.as_secs() as f64) + f64::from(elapsed.subsec_nanos());
+            // Duration in milliseconds: `subsec_nanos` alone is not a duration,
+            // so seconds and the sub-second remainder must be combined in the
+            // same unit rather than added as if they were already compatible.
+            let duration_ms = elapsed.as_secs() * 1_000 + u64::from(elapsed.subsec_millis());
             self.sink
                 .clone()
                 .histogram_with_labels("http_requests_duration", labels.clone())
-                .record_value(duration as u64);
+                .record_value(duration_ms);
         }
         self.sink
             .clone()
             .counter_with_labels("http_requests_total", labels.clone())
             .record(1);
+        self.sink
+            .clone()
+            .histogram_with_labels("http_response_size_bytes", labels.clone())
+            .record_value(response_size as u64);
     }
 
     fn metrics(&self) -> String {
         let x = self.exporter.clone().get_controller();
         let snapshot = x.snapshot();
-        let metrics: BTreeMap<String, String> = snapshot
-            .into_measurements()
-            .iter()
-            .map(|(k, v)| (format!("{}", k.name()), Metrics::print_measure(v)))
-            .collect();
-        serde_json::to_string(&metrics).unwrap()
+        match self.exposition {
+            Exposition::Json => {
+                let metrics: BTreeMap<String, String> = snapshot
+                    .into_measurements()
+                    .iter()
+                    .map(|(k, v)| (format!("{}", k.name()), Metrics::print_measure(v)))
+                    .collect();
+                serde_json::to_string(&metrics).unwrap()
+            }
+            Exposition::Prometheus => prometheus::render(
+                &self.namespace,
+                &snapshot,
+                self.duration_buckets.as_ref().map(Vec::as_slice),
+            ),
+        }
+    }
+
+    /// Content-Type to serve the metrics endpoint with, matching `exposition`.
+    fn content_type(&self) -> Option<http::HeaderValue> {
+        match self.exposition {
+            Exposition::Json => None,
+            Exposition::Prometheus => {
+                Some(http::HeaderValue::from_static("text/plain; version=0.0.4"))
+            }
+        }
     }
 
     fn print_measure(v: &Measurement) -> String {
@@ -178,16 +356,34 @@ where
         let req = res.request();
         let inner = self.inner.clone();
         let method = req.method().clone();
-        let path = req.path().to_string();
+        let raw_path = req.path().to_string();
+        let authorized = inner.is_authorized(req);
+        let path = if inner.use_path_pattern {
+            req.match_pattern()
+                .unwrap_or_else(|| UNMATCHED_PATH.to_string())
+        } else {
+            raw_path.clone()
+        };
 
         Ok(Async::Ready(res.map_body(move |mut head, mut body| {
             // We short circuit the response status and body to serve the endpoint
             // automagically. This way the user does not need to set the middleware *AND*
             // an endpoint to serve middleware results. The user is only required to set
-            // the middleware and tell us what the endpoint should be.
-            if inner.matches(&path, &method) {
-                head.status = StatusCode::OK;
-                body = ResponseBody::Other(Body::from_message(inner.metrics()));
+            // the middleware and tell us what the endpoint should be. The raw path is
+            // used here (rather than the `path` label below) because `/metrics` is
+            // typically not a registered resource and so has no match pattern.
+            if inner.matches(&raw_path, &method) {
+                if authorized {
+                    head.status = StatusCode::OK;
+                    if let Some(content_type) = inner.content_type() {
+                        head.headers
+                            .insert(http::header::CONTENT_TYPE, content_type);
+                    }
+                    body = ResponseBody::Other(Body::from_message(inner.metrics()));
+                } else {
+                    head.status = StatusCode::UNAUTHORIZED;
+                    body = ResponseBody::Other(Body::from_message(String::new()));
+                }
             }
             ResponseBody::Body(StreamLog {
                 body,
@@ -217,7 +413,7 @@ impl<B> Drop for StreamLog<B> {
     fn drop(&mut self) {
         // update the metrics for this request at the very end of responding
         self.inner
-            .update_metrics(&self.path, &self.method, self.status, self.clock);
+            .update_metrics(&self.path, &self.method, self.status, self.clock, self.size);
     }
 }
 
@@ -297,10 +493,75 @@ mod tests {
         assert_eq!(
             &body,
             &String::from_utf8(
-                web::Bytes::from(r#"{"http_requests_duration":"[0]","http_requests_total":"1"}"#)
+                web::Bytes::from(
+                    r#"{"http_requests_duration":"[0]","http_requests_total":"1","http_response_size_bytes":"[0]"}"#
+                )
                     .to_vec()
             )
             .unwrap()
         );
     }
+
+    #[test]
+    fn auth_token_required() {
+        let metrics = Metrics::new("/metrics", "actix_web_mw_test").with_auth_token("s3cr3t");
+
+        let mut app = init_service(App::new().wrap(metrics));
+
+        let res = call_service(&mut app, TestRequest::with_uri("/metrics").to_request());
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let res = call_service(
+            &mut app,
+            TestRequest::with_uri("/metrics")
+                .header(http::header::AUTHORIZATION, "Bearer wrong")
+                .to_request(),
+        );
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let res = call_service(
+            &mut app,
+            TestRequest::with_uri("/metrics")
+                .header(http::header::AUTHORIZATION, "Bearer s3cr3t")
+                .to_request(),
+        );
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn path_label_uses_match_pattern_not_raw_path() {
+        let metrics = Metrics::new_prometheus("/metrics", "actix_web_mw_test");
+
+        let mut app = init_service(
+            App::new()
+                .wrap(metrics)
+                .service(web::resource("/users/{id}").to(|| HttpResponse::Ok())),
+        );
+
+        call_service(&mut app, TestRequest::with_uri("/users/1").to_request());
+        call_service(&mut app, TestRequest::with_uri("/users/2").to_request());
+        call_service(
+            &mut app,
+            TestRequest::with_uri("/unregistered").to_request(),
+        );
+
+        let res = read_response(&mut app, TestRequest::with_uri("/metrics").to_request());
+        let body = String::from_utf8(res.to_vec()).unwrap();
+        println!("{}", body);
+
+        // Both requests matched the same resource, so they must collapse into
+        // a single `path="/users/{id}"` label with count 2, not two distinct
+        // raw-path labels.
+        assert!(body.contains(
+            "actix_web_mw_test_http_requests_total{path=\"/users/{id}\",method=\"GET\",status=\"200\"} 2\n"
+        ));
+        assert!(!body.contains("path=\"/users/1\""));
+        assert!(!body.contains("path=\"/users/2\""));
+
+        // A request to a path with no registered resource falls back to the
+        // `<unmatched>` label instead of leaking the raw path.
+        assert!(body.contains(
+            "actix_web_mw_test_http_requests_total{path=\"<unmatched>\",method=\"GET\",status=\"404\"} 1\n"
+        ));
+    }
 }
@@ -0,0 +1,302 @@
+//! Rendering of a `metrics_runtime::data::Snapshot` as Prometheus text
+//! exposition format, so the middleware can be scraped directly without a
+//! separate statsd-to-Prometheus bridge.
+
+use metrics_core::Label;
+use metrics_runtime::data::Snapshot;
+use metrics_runtime::Measurement;
+use std::collections::BTreeMap;
+
+/// Name of the request-latency histogram, as it is recorded by
+/// `Metrics::update_metrics` (before the namespace prefix is applied).
+const DURATION_METRIC: &str = "http_requests_duration";
+
+/// Render every measurement in `snapshot` as Prometheus text exposition
+/// format (version 0.0.4), prefixing each metric name with `namespace`.
+///
+/// `duration_buckets`, when set, overrides the derived-from-samples bucket
+/// bounds for the `http_requests_duration` histogram with explicit bounds
+/// (in milliseconds) configured via `Metrics::builder`.
+pub(crate) fn render(
+    namespace: &str,
+    snapshot: &Snapshot,
+    duration_buckets: Option<&[u64]>,
+) -> String {
+    let measurements = snapshot.into_measurements();
+    let entries: Vec<(String, Vec<Label>, &Measurement)> = measurements
+        .iter()
+        .map(|(key, measurement)| {
+            (
+                format!("{}", key.name()),
+                key.labels().cloned().collect(),
+                measurement,
+            )
+        })
+        .collect();
+
+    render_entries(
+        namespace,
+        entries
+            .iter()
+            .map(|(name, labels, measurement)| (name.as_str(), labels.as_slice(), *measurement)),
+        duration_buckets,
+    )
+}
+
+/// Render a flat list of `(metric name, labels, measurement)` entries,
+/// grouping by name so that a metric family's `# HELP`/`# TYPE` block is
+/// emitted exactly once — not once per `(Key, Measurement)` entry, since a
+/// single metric name routinely has several active label combinations (one
+/// per path/method/status seen so far).
+fn render_entries<'a, I>(namespace: &str, entries: I, duration_buckets: Option<&[u64]>) -> String
+where
+    I: Iterator<Item = (&'a str, &'a [Label], &'a Measurement)>,
+{
+    let mut by_name: BTreeMap<&'a str, Vec<(&'a [Label], &'a Measurement)>> = BTreeMap::new();
+    for (raw_name, labels, measurement) in entries {
+        by_name
+            .entry(raw_name)
+            .or_insert_with(Vec::new)
+            .push((labels, measurement));
+    }
+
+    let mut out = String::new();
+    for (raw_name, group) in by_name {
+        let name = format!("{}_{}", namespace, raw_name);
+        let kind = match group[0].1 {
+            Measurement::Counter(_) => "counter",
+            Measurement::Gauge(_) => "gauge",
+            Measurement::Histogram(_) => "histogram",
+        };
+        push_help_and_type(&mut out, &name, raw_name, kind);
+
+        for (labels, measurement) in group {
+            match measurement {
+                Measurement::Counter(value) => {
+                    out.push_str(&format!(
+                        "{}{} {}\n",
+                        name,
+                        render_labels(labels.iter()),
+                        value
+                    ));
+                }
+                Measurement::Gauge(value) => {
+                    out.push_str(&format!(
+                        "{}{} {}\n",
+                        name,
+                        render_labels(labels.iter()),
+                        value
+                    ));
+                }
+                Measurement::Histogram(histogram) => {
+                    let samples = histogram.decompress();
+                    let bounds = if raw_name == DURATION_METRIC {
+                        duration_buckets
+                    } else {
+                        None
+                    };
+                    render_histogram(&mut out, &name, labels.iter(), &samples, bounds);
+                }
+            }
+        }
+    }
+    out
+}
+
+fn push_help_and_type(out: &mut String, name: &str, raw_name: &str, kind: &str) {
+    out.push_str(&format!("# HELP {} {}\n", name, help_text(raw_name)));
+    out.push_str(&format!("# TYPE {} {}\n", name, kind));
+}
+
+/// A human-readable description for the `# HELP` line. Falls back to a
+/// generic description for application-defined metrics published through
+/// `Metrics::sink`, which this module has no prior knowledge of.
+fn help_text(raw_name: &str) -> String {
+    match raw_name {
+        "http_requests_total" => "Total number of HTTP requests processed.".to_string(),
+        DURATION_METRIC => "HTTP request latency in milliseconds.".to_string(),
+        "http_response_size_bytes" => "HTTP response body size in bytes.".to_string(),
+        _ => format!("{} (application-defined metric).", raw_name),
+    }
+}
+
+fn render_histogram<'a, I>(
+    out: &mut String,
+    name: &str,
+    labels: I,
+    samples: &[u64],
+    explicit_bounds: Option<&[u64]>,
+) where
+    I: Iterator<Item = &'a Label> + Clone,
+{
+    let mut sorted = samples.to_vec();
+    sorted.sort_unstable();
+
+    let bounds = match explicit_bounds {
+        Some(explicit) => {
+            let mut bounds = explicit.to_vec();
+            bounds.sort_unstable();
+            bounds.dedup();
+            bounds
+        }
+        None => {
+            let mut bounds = sorted.clone();
+            bounds.dedup();
+            bounds
+        }
+    };
+
+    for bound in &bounds {
+        let cumulative = sorted.iter().take_while(|v| *v <= bound).count();
+        out.push_str(&format!(
+            "{}_bucket{} {}\n",
+            name,
+            render_bucket_labels(labels.clone(), &bound.to_string()),
+            cumulative
+        ));
+    }
+
+    let count = sorted.len();
+    out.push_str(&format!(
+        "{}_bucket{} {}\n",
+        name,
+        render_bucket_labels(labels.clone(), "+Inf"),
+        count
+    ));
+
+    let sum: u64 = sorted.iter().sum();
+    out.push_str(&format!(
+        "{}_sum{} {}\n",
+        name,
+        render_labels(labels.clone()),
+        sum
+    ));
+    out.push_str(&format!(
+        "{}_count{} {}\n",
+        name,
+        render_labels(labels),
+        count
+    ));
+}
+
+fn render_labels<'a, I: Iterator<Item = &'a Label>>(labels: I) -> String {
+    let rendered: Vec<String> = labels
+        .map(|l| format!("{}=\"{}\"", l.key(), escape_label_value(l.value())))
+        .collect();
+    if rendered.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", rendered.join(","))
+    }
+}
+
+fn render_bucket_labels<'a, I: Iterator<Item = &'a Label>>(labels: I, le: &str) -> String {
+    let mut rendered: Vec<String> = labels
+        .map(|l| format!("{}=\"{}\"", l.key(), escape_label_value(l.value())))
+        .collect();
+    rendered.push(format!("le=\"{}\"", escape_label_value(le)));
+    format!("{{{}}}", rendered.join(","))
+}
+
+/// Escape `\`, `"` and newlines per the Prometheus text format label-value
+/// grammar. Order matters: backslashes must be escaped before the
+/// characters that escaping introduces.
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_label_values() {
+        assert_eq!(escape_label_value("plain"), "plain");
+        assert_eq!(escape_label_value("a\"b"), "a\\\"b");
+        assert_eq!(escape_label_value("a\\b"), "a\\\\b");
+        assert_eq!(escape_label_value("a\nb"), "a\\nb");
+        // backslashes must be escaped first, or a literal backslash
+        // followed by an escaped character would read as a single escape.
+        assert_eq!(escape_label_value("a\\\"b"), "a\\\\\\\"b");
+    }
+
+    #[test]
+    fn render_labels_empty_and_nonempty() {
+        assert_eq!(render_labels(std::iter::empty::<&Label>()), "");
+
+        let labels: Vec<Label> = labels!("path" => "/x".to_string(), "method" => "GET".to_string());
+        assert_eq!(render_labels(labels.iter()), "{path=\"/x\",method=\"GET\"}");
+    }
+
+    #[test]
+    fn render_histogram_emits_cumulative_buckets_and_inf() {
+        let mut out = String::new();
+        let labels: Vec<Label> = labels!("path" => "/x".to_string());
+        render_histogram(&mut out, "duration", labels.iter(), &[10, 20, 20, 50], None);
+
+        assert!(out.contains("duration_bucket{path=\"/x\",le=\"10\"} 1\n"));
+        assert!(out.contains("duration_bucket{path=\"/x\",le=\"20\"} 3\n"));
+        assert!(out.contains("duration_bucket{path=\"/x\",le=\"50\"} 4\n"));
+        assert!(out.contains("duration_bucket{path=\"/x\",le=\"+Inf\"} 4\n"));
+        assert!(out.contains("duration_sum{path=\"/x\"} 100\n"));
+        assert!(out.contains("duration_count{path=\"/x\"} 4\n"));
+    }
+
+    #[test]
+    fn render_histogram_honors_explicit_bounds() {
+        let mut out = String::new();
+        let labels: Vec<Label> = labels!("path" => "/x".to_string());
+        render_histogram(
+            &mut out,
+            "duration",
+            labels.iter(),
+            &[5, 15, 150],
+            Some(&[10, 100]),
+        );
+
+        assert!(out.contains("duration_bucket{path=\"/x\",le=\"10\"} 1\n"));
+        assert!(out.contains("duration_bucket{path=\"/x\",le=\"100\"} 2\n"));
+        assert!(out.contains("duration_bucket{path=\"/x\",le=\"+Inf\"} 3\n"));
+    }
+
+    #[test]
+    fn help_text_does_not_stutter_the_metric_name() {
+        assert_eq!(
+            help_text("http_requests_total"),
+            "Total number of HTTP requests processed."
+        );
+        assert!(!help_text("custom_gauge").ends_with("custom_gauge metric"));
+    }
+
+    #[test]
+    fn render_entries_emits_help_and_type_once_per_metric_name() {
+        let get_labels: Vec<Label> = labels!("method" => "GET".to_string());
+        let post_labels: Vec<Label> = labels!("method" => "POST".to_string());
+        let get = Measurement::Counter(1);
+        let post = Measurement::Counter(2);
+        let entries = vec![
+            ("http_requests_total", get_labels.as_slice(), &get),
+            ("http_requests_total", post_labels.as_slice(), &post),
+        ];
+
+        let out = render_entries("app", entries.into_iter(), None);
+
+        assert_eq!(
+            out.matches("# HELP app_http_requests_total").count(),
+            1,
+            "HELP line must appear exactly once per metric family, not once per label set:\n{}",
+            out
+        );
+        assert_eq!(
+            out.matches("# TYPE app_http_requests_total").count(),
+            1,
+            "TYPE line must appear exactly once per metric family, not once per label set:\n{}",
+            out
+        );
+        assert!(out.contains("app_http_requests_total{method=\"GET\"} 1\n"));
+        assert!(out.contains("app_http_requests_total{method=\"POST\"} 2\n"));
+    }
+}